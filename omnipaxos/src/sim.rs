@@ -0,0 +1,348 @@
+//! Deterministic simulation harness for a cluster of OmniPaxos instances.
+//!
+//! The harness owns every node's logical clock and an in-memory message bus so
+//! a test controls the exact interleaving of ticks and message delivery. Because
+//! `LogicalClock`, `ELECTION_TIMEOUT`, `RESEND_MESSAGE_TIMEOUT` and message
+//! sequencing are all logical rather than wall-clock, the driver advances time
+//! explicitly and runs a cluster to quiescence from a single seed. Packet drop,
+//! duplication, reordering and partition/heal events are driven by a seeded RNG,
+//! and every delivered or dropped message is appended to an [`EventTrace`] so a
+//! failing seed can be replayed deterministically.
+//!
+//! The module is test-only infrastructure and is meant to be declared from the
+//! crate root behind the `sim` feature (`#[cfg(feature = "sim")] pub mod sim;`,
+//! with `sim = []` added to the `[features]` table). The `#![cfg]` below gates
+//! the module body on that feature.
+#![cfg(feature = "sim")]
+
+use crate::{
+    messages::Message,
+    storage::{Entry, Storage},
+    util::{LogEntry, NodeId},
+    OmniPaxos,
+};
+use std::collections::HashMap;
+
+/// A small, dependency-free xorshift64* generator. Deterministic given a seed so
+/// the whole simulation is reproducible from a single `u64`.
+#[derive(Copy, Clone, Debug)]
+pub struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    /// Creates a generator from `seed`. A zero seed is remapped so the stream is
+    /// never stuck at zero.
+    pub fn from_seed(seed: u64) -> Self {
+        Self {
+            state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed },
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.state = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Returns `true` with probability `p` (clamped to `[0.0, 1.0]`).
+    fn chance(&mut self, p: f64) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        if p >= 1.0 {
+            return true;
+        }
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64 < p
+    }
+}
+
+/// Probabilities governing how the message bus mistreats in-flight messages.
+/// All probabilities default to zero, i.e. a perfect network.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FaultConfig {
+    /// Probability that a message is dropped on delivery.
+    pub drop: f64,
+    /// Probability that a message is duplicated (delivered an extra time).
+    pub duplicate: f64,
+    /// Probability that a message is deferred behind later ones (reordering).
+    pub reorder: f64,
+}
+
+/// A message sitting in the bus, tagged with its route.
+#[derive(Clone, Debug)]
+struct InFlight<T: Entry> {
+    from: NodeId,
+    to: NodeId,
+    msg: Message<T>,
+}
+
+/// One entry in the replayable trace of what the harness did.
+#[derive(Clone, Debug)]
+pub enum SimEvent {
+    /// A message was delivered to its destination.
+    Delivered { from: NodeId, to: NodeId },
+    /// A message was dropped by the fault injector.
+    Dropped { from: NodeId, to: NodeId },
+    /// A message was duplicated.
+    Duplicated { from: NodeId, to: NodeId },
+    /// A message was deferred behind later traffic.
+    Reordered { from: NodeId, to: NodeId },
+    /// A node's logical clock was ticked.
+    Ticked(NodeId),
+    /// The link between two nodes changed reachability.
+    Partition { a: NodeId, b: NodeId, reachable: bool },
+}
+
+/// The ordered record of every event the harness performed, sufficient to
+/// replay a run. A failing seed plus this trace pinpoints the interleaving.
+pub type EventTrace = Vec<SimEvent>;
+
+/// A deterministic driver for a cluster of OmniPaxos instances sharing a seeded
+/// RNG, an in-memory bus and a single-threaded run-to-quiescence loop.
+pub struct Simulation<T, B>
+where
+    T: Entry,
+    B: Storage<T>,
+{
+    nodes: HashMap<NodeId, OmniPaxos<T, B>>,
+    bus: Vec<InFlight<T>>,
+    /// Unreachable ordered node pairs `(from, to)`; a partition blocks both ways
+    /// when both directions are inserted.
+    partitions: Vec<(NodeId, NodeId)>,
+    faults: FaultConfig,
+    rng: SimRng,
+    trace: EventTrace,
+    /// The decided entry observed at each log index, used to assert the
+    /// agreement invariant across steps.
+    decided_by_index: HashMap<usize, T>,
+    /// The largest decided index seen per node, used to assert prefixes never
+    /// shrink.
+    max_decided_idx: HashMap<NodeId, usize>,
+}
+
+impl<T, B> Simulation<T, B>
+where
+    T: Entry + PartialEq,
+    B: Storage<T>,
+{
+    /// Creates a harness over `nodes` with a perfect network, seeded by `seed`.
+    pub fn new(nodes: Vec<(NodeId, OmniPaxos<T, B>)>, seed: u64) -> Self {
+        Self {
+            nodes: nodes.into_iter().collect(),
+            bus: Vec::new(),
+            partitions: Vec::new(),
+            faults: FaultConfig::default(),
+            rng: SimRng::from_seed(seed),
+            trace: Vec::new(),
+            decided_by_index: HashMap::new(),
+            max_decided_idx: HashMap::new(),
+        }
+    }
+
+    /// Sets the fault probabilities applied to in-flight messages.
+    pub fn with_faults(mut self, faults: FaultConfig) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    /// A mutable handle to a node, e.g. to append proposals between steps.
+    pub fn node_mut(&mut self, pid: NodeId) -> Option<&mut OmniPaxos<T, B>> {
+        self.nodes.get_mut(&pid)
+    }
+
+    /// Partitions `a` and `b` from each other until [`Self::heal`] is called.
+    pub fn partition(&mut self, a: NodeId, b: NodeId) {
+        for pair in [(a, b), (b, a)] {
+            if !self.partitions.contains(&pair) {
+                self.partitions.push(pair);
+            }
+        }
+        self.trace.push(SimEvent::Partition {
+            a,
+            b,
+            reachable: false,
+        });
+    }
+
+    /// Restores reachability between `a` and `b`.
+    pub fn heal(&mut self, a: NodeId, b: NodeId) {
+        self.partitions
+            .retain(|pair| *pair != (a, b) && *pair != (b, a));
+        self.trace.push(SimEvent::Partition {
+            a,
+            b,
+            reachable: true,
+        });
+    }
+
+    fn reachable(&self, from: NodeId, to: NodeId) -> bool {
+        !self.partitions.contains(&(from, to))
+    }
+
+    /// Ticks every node's logical clock once and collects its outgoing messages
+    /// onto the bus. One call to this is one unit of logical time.
+    pub fn tick(&mut self) {
+        // Tick in pid order: `HashMap` iteration order differs between runs.
+        let mut pids: Vec<NodeId> = self.nodes.keys().copied().collect();
+        pids.sort_unstable();
+        for pid in pids {
+            if let Some(node) = self.nodes.get_mut(&pid) {
+                node.tick();
+                self.trace.push(SimEvent::Ticked(pid));
+                let outgoing = node.outgoing_messages();
+                for msg in outgoing {
+                    let to = msg.get_receiver();
+                    self.bus.push(InFlight { from: pid, to, msg });
+                }
+            }
+        }
+    }
+
+    /// Delivers one round of bus traffic, applying the configured faults, then
+    /// collects any messages the deliveries produced. Returns `true` if any
+    /// message was actually delivered (i.e. progress may have been made).
+    pub fn deliver_round(&mut self) -> bool {
+        let pending = std::mem::take(&mut self.bus);
+        let mut deferred = Vec::new();
+        let mut progressed = false;
+        for m in pending {
+            if !self.reachable(m.from, m.to) || self.nodes.get(&m.to).is_none() {
+                self.trace.push(SimEvent::Dropped {
+                    from: m.from,
+                    to: m.to,
+                });
+                continue;
+            }
+            if self.faults.drop > 0.0 && self.rng.chance(self.faults.drop) {
+                self.trace.push(SimEvent::Dropped {
+                    from: m.from,
+                    to: m.to,
+                });
+                continue;
+            }
+            if self.faults.reorder > 0.0 && self.rng.chance(self.faults.reorder) {
+                self.trace.push(SimEvent::Reordered {
+                    from: m.from,
+                    to: m.to,
+                });
+                deferred.push(m);
+                continue;
+            }
+            let dup = self.faults.duplicate > 0.0 && self.rng.chance(self.faults.duplicate);
+            self.apply(m.clone());
+            progressed = true;
+            if dup {
+                self.trace.push(SimEvent::Duplicated {
+                    from: m.from,
+                    to: m.to,
+                });
+                self.apply(m);
+            }
+        }
+        // Deferred messages go back on the bus to be delivered in a later round.
+        self.bus.extend(deferred);
+        progressed
+    }
+
+    fn apply(&mut self, m: InFlight<T>) {
+        if let Some(node) = self.nodes.get_mut(&m.to) {
+            self.trace.push(SimEvent::Delivered {
+                from: m.from,
+                to: m.to,
+            });
+            node.handle(m.msg);
+            let outgoing = node.outgoing_messages();
+            for msg in outgoing {
+                let to = msg.get_receiver();
+                self.bus.push(InFlight {
+                    from: m.to,
+                    to,
+                    msg,
+                });
+            }
+        }
+    }
+
+    /// Runs the cluster to quiescence: repeatedly delivers the bus and ticks
+    /// until no message is in flight and a tick produces none, or `max_steps`
+    /// is reached. Safety invariants are checked after every step. Returns the
+    /// number of steps taken.
+    pub fn run_to_quiescence(&mut self, max_steps: usize) -> usize {
+        let mut steps = 0;
+        for _ in 0..max_steps {
+            steps += 1;
+            let delivered = self.deliver_round();
+            self.check_invariants();
+            if !delivered && self.bus.is_empty() {
+                // Nudge logical time forward; a tick may wake a resend/election.
+                self.tick();
+                self.check_invariants();
+                if self.bus.is_empty() {
+                    break;
+                }
+            }
+        }
+        steps
+    }
+
+    /// Asserts the core safety invariants: no two nodes have decided different
+    /// entries at the same index, and no node's decided prefix has shrunk.
+    pub fn check_invariants(&mut self) {
+        let pids: Vec<NodeId> = self.nodes.keys().copied().collect();
+        for pid in pids {
+            let decided_idx = match self.nodes.get(&pid) {
+                Some(node) => node.get_decided_idx(),
+                None => continue,
+            };
+            let prev = self.max_decided_idx.entry(pid).or_insert(0);
+            assert!(
+                decided_idx >= *prev,
+                "decided prefix of node {} shrank from {} to {}",
+                pid,
+                *prev,
+                decided_idx
+            );
+            *prev = decided_idx;
+
+            if let Some(node) = self.nodes.get_mut(&pid) {
+                if let Some(entries) = node.read_decided_suffix(0) {
+                    // Track the absolute log index as we walk the suffix. A
+                    // compacted node returns Trimmed/Snapshotted variants at the
+                    // front, so the enumerate() offset is not the log index; we
+                    // advance past the compacted prefix explicitly so decided
+                    // entries are compared across nodes at the same true index.
+                    let mut idx = 0;
+                    for entry in entries.iter() {
+                        match entry {
+                            LogEntry::Trimmed(trimmed_idx) => idx = *trimmed_idx,
+                            LogEntry::Snapshotted(s) => idx = s.trimmed_idx,
+                            LogEntry::Decided(v) => {
+                                match self.decided_by_index.get(&idx) {
+                                    Some(existing) => assert!(
+                                        existing == v,
+                                        "nodes disagree on decided entry at index {}",
+                                        idx
+                                    ),
+                                    None => {
+                                        self.decided_by_index.insert(idx, v.clone());
+                                    }
+                                }
+                                idx += 1;
+                            }
+                            _ => idx += 1,
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the event trace recorded so far so a failing seed can be replayed.
+    pub fn trace(&self) -> &EventTrace {
+        &self.trace
+    }
+}
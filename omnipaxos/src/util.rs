@@ -8,6 +8,37 @@ use nohash_hasher::IntMap;
 use serde::{Deserialize, Serialize};
 use std::{cmp::Ordering, marker::PhantomData};
 
+/// A 256-bit SHA-256 digest identifying a large-entry payload stored out-of-line
+/// in the preimage side table. The digest is stable across Rust versions and
+/// platforms, so every node derives the same hash for the same payload.
+pub type PreimageHash = [u8; 32];
+
+/// A bounded reference that replaces a large payload in the replicated log. Only
+/// the hash and length travel inside `Accept`/retransmission/`LogSync` payloads;
+/// the full bytes are fetched on demand via [`FetchPreimage`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PreimageRef {
+    /// Content hash of the payload, computed over its canonical serialized form
+    /// so identical commands share a reference.
+    pub hash: PreimageHash,
+    /// Serialized length of the payload in bytes. Fixed-width so the reference
+    /// serializes identically on every platform.
+    pub len: u64,
+}
+
+/// Map from a preimage's content hash to its serialized payload.
+pub type PreimageMap = std::collections::HashMap<PreimageHash, Vec<u8>>;
+
+/// Requests the payload behind a [`PreimageRef`] whose hash the receiver does
+/// not yet hold. The leader, or any peer holding it, replies with the bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FetchPreimage {
+    /// The content hash of the missing payload.
+    pub hash: PreimageHash,
+}
+
 /// Struct used to help another server synchronize their log with the current state of our own log.
 #[derive(Clone, Debug)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -23,6 +54,38 @@ where
     pub sync_idx: usize,
     /// The accepted StopSign.
     pub stopsign: Option<StopSign>,
+    /// Payloads for the preimage references contained in `suffix`/`decided_snapshot`,
+    /// limited to those the syncing follower is missing. References whose hash is
+    /// absent here must be fetched with [`FetchPreimage`].
+    #[cfg(feature = "preimage")]
+    pub preimages: PreimageMap,
+}
+
+/// Sent by a follower that detects a gap in the accept-phase message sequence
+/// (i.e. [`MessageStatus::DroppedPreceding`]). It asks the leader to replay the
+/// missing suffix in the current session instead of forcing a full re-prepare.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Resync {
+    /// The session the follower believes it is in. The leader ignores the
+    /// request if this does not match the follower's current session.
+    pub session: u64,
+    /// The highest contiguous counter the follower has processed in `session`.
+    pub last_good_counter: u64,
+}
+
+/// The outcome of a leader honoring a [`Resync`] request from a follower.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ResyncOutcome {
+    /// Replay the accept suffix from this log index onwards with fresh counters
+    /// in the same session. The index is the first entry the follower is
+    /// missing (an exclusive accepted index), so the replay resends `[idx..]`.
+    Replay(usize),
+    /// The request refers to an older session and is ignored.
+    Outdated,
+    /// The leader can no longer reconstruct the suffix (e.g. it was compacted);
+    /// fall back to incrementing the session and sending a `LogSync`.
+    Fallback,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -87,6 +150,11 @@ where
     max_promise_meta: PromiseMetaData,
     max_promise_sync: Option<LogSync<T>>,
     latest_accept_meta: NodeMap<Option<(Ballot, usize)>>, //  index in outgoing
+    // For each follower, the log index reached by each accept-phase counter in the
+    // current session. `accept_suffix_idxs[pid][counter - 1]` is the accepted index
+    // the follower should have after processing the accept with that counter, letting
+    // a `Resync` map its `last_good_counter` back to a log index for replay.
+    accept_suffix_idxs: NodeMap<Vec<usize>>,
     // The number of promises needed in the prepare phase to become synced and
     // the number of accepteds needed in the accept phase to decide an entry.
     pub quorum: Quorum,
@@ -101,6 +169,7 @@ where
         let mut follower_seq_nums = NodeMap::default();
         let mut accepted_indexes = NodeMap::default();
         let mut latest_accept_meta = NodeMap::default();
+        let mut accept_suffix_idxs = NodeMap::default();
 
         // Initialize maps for all peers
         for &peer in peers.iter() {
@@ -108,6 +177,7 @@ where
             follower_seq_nums.insert(peer, SequenceNumber::default());
             accepted_indexes.insert(peer, 0);
             latest_accept_meta.insert(peer, None);
+            accept_suffix_idxs.insert(peer, Vec::new());
         }
 
         Self {
@@ -118,6 +188,7 @@ where
             max_promise_meta: PromiseMetaData::default(),
             max_promise_sync: None,
             latest_accept_meta,
+            accept_suffix_idxs,
             quorum,
         }
     }
@@ -127,6 +198,61 @@ where
             seq_num.session += 1;
             seq_num.counter = 0;
         }
+        if let Some(idxs) = self.accept_suffix_idxs.get_mut(&pid) {
+            idxs.clear();
+        }
+    }
+
+    /// Records that the accept sent to `pid` with the latest counter brings the
+    /// follower up to `accepted_idx` (the exclusive accepted index after the
+    /// accept is applied), so a later [`Resync`] can map a counter back to the
+    /// first entry the follower would be missing. Must be called right after
+    /// [`Self::next_seq_num`] when sending an `Accept`/`AcceptDecide`.
+    pub fn record_accept_suffix(&mut self, pid: NodeId, accepted_idx: usize) {
+        self.accept_suffix_idxs
+            .entry(pid)
+            .or_default()
+            .push(accepted_idx);
+    }
+
+    /// Honors a [`Resync`] from follower `pid`. A resync is only honored if its
+    /// session matches the follower's current session; older sessions are
+    /// [`ResyncOutcome::Outdated`]. If the suffix from `last_good_counter` can
+    /// still be reconstructed the follower's counter is rewound so the missing
+    /// suffix is replayed with fresh counters in the same session
+    /// ([`ResyncOutcome::Replay`]); otherwise the caller must
+    /// [`increment_seq_num_session`](Self::increment_seq_num_session) and send a
+    /// `LogSync` ([`ResyncOutcome::Fallback`]).
+    pub fn handle_resync(
+        &mut self,
+        pid: NodeId,
+        resync: Resync,
+        compacted_idx: usize,
+    ) -> ResyncOutcome {
+        let seq_num = match self.follower_seq_nums.get_mut(&pid) {
+            Some(seq_num) if seq_num.session == resync.session => seq_num,
+            _ => return ResyncOutcome::Outdated,
+        };
+        let idxs = match self.accept_suffix_idxs.get_mut(&pid) {
+            Some(idxs) => idxs,
+            None => return ResyncOutcome::Fallback,
+        };
+        // `last_good_counter` is the counter of the last accept the follower
+        // processed (1-indexed). The recorded value is the exclusive accepted
+        // index that accept left the follower at, so it is exactly the first
+        // entry the follower is missing -- the replay starts there, not one past
+        // it.
+        let slot = (resync.last_good_counter as usize).checked_sub(1);
+        match slot.and_then(|i| idxs.get(i)).copied() {
+            Some(from_idx) if from_idx >= compacted_idx => {
+                seq_num.counter = resync.last_good_counter;
+                // Drop the slots of the accepts being replayed so the replayed
+                // accepts are recorded at their new counters.
+                idxs.truncate(resync.last_good_counter as usize);
+                ResyncOutcome::Replay(from_idx)
+            }
+            _ => ResyncOutcome::Fallback,
+        }
     }
 
     pub fn next_seq_num(&mut self, pid: NodeId) -> SequenceNumber {
@@ -350,6 +476,194 @@ pub(crate) mod defaults {
     pub(crate) const ELECTION_TIMEOUT: u64 = 1;
     pub(crate) const RESEND_MESSAGE_TIMEOUT: u64 = 100;
     pub(crate) const FLUSH_BATCH_TIMEOUT: u64 = 200;
+    /// Serialized entries larger than this many bytes are stored out-of-line in
+    /// the preimage side table and replaced by a [`PreimageRef`] in the log.
+    #[cfg(feature = "preimage")]
+    pub(crate) const PREIMAGE_THRESHOLD_BYTES: usize = 8192;
+}
+
+#[cfg(feature = "preimage")]
+impl PreimageRef {
+    /// Builds a reference for a serialized payload.
+    pub(crate) fn of(bytes: &[u8]) -> Self {
+        Self {
+            hash: preimage_hash(bytes),
+            len: bytes.len() as u64,
+        }
+    }
+}
+
+/// Computes the SHA-256 digest of a serialized payload. SHA-256 is specified
+/// bit-for-bit, so every node agrees on the digest regardless of build, and its
+/// collision resistance makes it safe to key a replicated log by.
+#[cfg(feature = "preimage")]
+pub(crate) fn preimage_hash(bytes: &[u8]) -> PreimageHash {
+    sha256::digest(bytes)
+}
+
+/// A content-addressed side table holding the payloads that were too large to
+/// inline in the log, keyed by their [`PreimageHash`]. Identical payloads store
+/// a single entry, refcounted by the number of referencing log positions so a
+/// payload is only dropped once every referencing entry is trimmed or
+/// snapshotted below the decided index.
+#[cfg(feature = "preimage")]
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PreimageTable {
+    payloads: PreimageMap,
+    refcounts: std::collections::HashMap<PreimageHash, usize>,
+}
+
+#[cfg(feature = "preimage")]
+impl PreimageTable {
+    /// Stores `bytes`, returning the bounded reference that replaces it in the
+    /// log and bumping the reference count for the new log position.
+    pub(crate) fn store(&mut self, bytes: Vec<u8>) -> PreimageRef {
+        let reference = PreimageRef::of(&bytes);
+        self.payloads.entry(reference.hash).or_insert(bytes);
+        *self.refcounts.entry(reference.hash).or_insert(0) += 1;
+        reference
+    }
+
+    /// Returns the payload behind `reference`, or `None` if it is not held (the
+    /// caller must issue a [`FetchPreimage`]).
+    pub(crate) fn get(&self, reference: &PreimageRef) -> Option<&[u8]> {
+        self.payloads.get(&reference.hash).map(Vec::as_slice)
+    }
+
+    /// The substitution decision applied when building an `Accept`/`LogSync`
+    /// entry: payloads larger than [`defaults::PREIMAGE_THRESHOLD_BYTES`] are
+    /// stored out-of-line and replaced by a [`PreimageRef`]; payloads up to the
+    /// threshold stay inline and return `None`.
+    pub(crate) fn out_line(&mut self, bytes: Vec<u8>) -> Option<PreimageRef> {
+        if bytes.len() > defaults::PREIMAGE_THRESHOLD_BYTES {
+            Some(self.store(bytes))
+        } else {
+            None
+        }
+    }
+
+    /// Resolves `reference` on read so `Entry` consumers never observe the hash
+    /// form: returns the payload if held, otherwise the [`FetchPreimage`] the
+    /// caller must send to obtain it.
+    pub(crate) fn resolve(&self, reference: &PreimageRef) -> Result<&[u8], FetchPreimage> {
+        self.get(reference)
+            .ok_or(FetchPreimage { hash: reference.hash })
+    }
+
+    /// Inserts a payload received in a `LogSync` or `FetchPreimage` reply,
+    /// registering a reference for the position that requested it.
+    pub(crate) fn insert(&mut self, hash: PreimageHash, bytes: Vec<u8>) {
+        self.payloads.entry(hash).or_insert(bytes);
+        *self.refcounts.entry(hash).or_insert(0) += 1;
+    }
+
+    /// Drops one reference to `hash`; once no log position references it the
+    /// payload is garbage-collected. Called when a referencing entry is trimmed
+    /// or snapshotted below the decided index.
+    pub(crate) fn release(&mut self, hash: &PreimageHash) {
+        if let Some(count) = self.refcounts.get_mut(hash) {
+            *count -= 1;
+            if *count == 0 {
+                self.refcounts.remove(hash);
+                self.payloads.remove(hash);
+            }
+        }
+    }
+
+    /// Collects the payloads for `refs` that are held locally, for inclusion in
+    /// a [`LogSync`]. References whose payload is absent are left for the
+    /// follower to fetch explicitly.
+    pub(crate) fn collect<'a>(
+        &self,
+        refs: impl IntoIterator<Item = &'a PreimageRef>,
+    ) -> PreimageMap {
+        refs.into_iter()
+            .filter_map(|r| self.payloads.get(&r.hash).map(|b| (r.hash, b.clone())))
+            .collect()
+    }
+}
+
+/// Minimal, dependency-free SHA-256 (FIPS 180-4) used to content-address large
+/// payloads deterministically across nodes.
+#[cfg(feature = "preimage")]
+mod sha256 {
+    use super::PreimageHash;
+
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4,
+        0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe,
+        0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f,
+        0x4a7484aa, 0x5cb0a9dc, 0x76f988da, 0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7,
+        0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc,
+        0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070, 0x19a4c116,
+        0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+        0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7,
+        0xc67178f2,
+    ];
+
+    /// Returns the SHA-256 digest of `bytes`.
+    pub(super) fn digest(bytes: &[u8]) -> PreimageHash {
+        let mut h: [u32; 8] = [
+            0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+            0x5be0cd19,
+        ];
+
+        // Pad: message, 0x80, zeros, then the 64-bit big-endian bit length.
+        let bit_len = (bytes.len() as u64).wrapping_mul(8);
+        let mut msg = bytes.to_vec();
+        msg.push(0x80);
+        while msg.len() % 64 != 56 {
+            msg.push(0);
+        }
+        msg.extend_from_slice(&bit_len.to_be_bytes());
+
+        for block in msg.chunks_exact(64) {
+            let mut w = [0u32; 64];
+            for (i, word) in block.chunks_exact(4).enumerate() {
+                w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+            }
+            for i in 16..64 {
+                let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+                let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+                w[i] = w[i - 16]
+                    .wrapping_add(s0)
+                    .wrapping_add(w[i - 7])
+                    .wrapping_add(s1);
+            }
+
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+            for i in 0..64 {
+                let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+                let ch = (e & f) ^ ((!e) & g);
+                let t1 = hh
+                    .wrapping_add(s1)
+                    .wrapping_add(ch)
+                    .wrapping_add(K[i])
+                    .wrapping_add(w[i]);
+                let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+                let maj = (a & b) ^ (a & c) ^ (b & c);
+                let t2 = s0.wrapping_add(maj);
+                hh = g;
+                g = f;
+                f = e;
+                e = d.wrapping_add(t1);
+                d = c;
+                c = b;
+                b = a;
+                a = t1.wrapping_add(t2);
+            }
+            for (dst, v) in h.iter_mut().zip([a, b, c, d, e, f, g, hh]) {
+                *dst = dst.wrapping_add(v);
+            }
+        }
+
+        let mut out = [0u8; 32];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(h) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
 }
 
 #[allow(missing_docs)]
@@ -397,6 +711,30 @@ impl SequenceNumber {
             MessageStatus::DroppedPreceding
         }
     }
+
+    /// Builds the [`Resync`] a follower sends after detecting a
+    /// [`MessageStatus::DroppedPreceding`], carrying the highest contiguous
+    /// sequence number it has processed.
+    pub(crate) fn as_resync(&self) -> Resync {
+        Resync {
+            session: self.session,
+            last_good_counter: self.counter,
+        }
+    }
+}
+
+impl MessageStatus {
+    /// Given the follower's current accept sequence number, returns the
+    /// [`Resync`] it should send the leader when a gap is detected, or `None`
+    /// when the status does not warrant a resync. This is the single entry point
+    /// the accept-handling path uses so [`SequenceNumber::as_resync`] is only
+    /// emitted on [`MessageStatus::DroppedPreceding`].
+    pub(crate) fn resync_request(&self, current: SequenceNumber) -> Option<Resync> {
+        match self {
+            MessageStatus::DroppedPreceding => Some(current.as_resync()),
+            MessageStatus::Expected | MessageStatus::Outdated => None,
+        }
+    }
 }
 
 pub(crate) struct LogicalClock {
@@ -508,4 +846,142 @@ mod tests {
         let prep_peers = leader_state.get_preparable_peers(&nodes);
         assert_eq!(prep_peers, nodes);
     }
+
+    #[test]
+    fn resync_replay_and_fallback_test() {
+        // `Entry` for `()` is implemented in `preparable_peers_test`.
+        type Value = ();
+
+        let nodes = vec![2, 3];
+        let quorum = Quorum::Majority(2);
+        let mut leader_state =
+            LeaderState::<Value>::with(Ballot::with(1, 1, 1, 3), &nodes, quorum);
+
+        // Follower 2 receives three accepts reaching indexes 5, 8 and 12.
+        for idx in [5, 8, 12] {
+            leader_state.next_seq_num(2);
+            leader_state.record_accept_suffix(2, idx);
+        }
+        let session = leader_state.get_seq_num(2).session;
+
+        // A resync for the current session whose last good counter is still in
+        // the log replays from that counter's index and rewinds the counter.
+        let resync = Resync {
+            session,
+            last_good_counter: 2,
+        };
+        assert_eq!(
+            leader_state.handle_resync(2, resync, 0),
+            ResyncOutcome::Replay(8)
+        );
+        assert_eq!(leader_state.get_seq_num(2).counter, 2);
+
+        // A follower only emits a Resync on a detected gap, carrying its highest
+        // contiguous sequence number.
+        let current = SequenceNumber {
+            session,
+            counter: 2,
+        };
+        assert_eq!(
+            MessageStatus::DroppedPreceding.resync_request(current),
+            Some(Resync {
+                session,
+                last_good_counter: 2
+            })
+        );
+        assert_eq!(MessageStatus::Expected.resync_request(current), None);
+        assert_eq!(MessageStatus::Outdated.resync_request(current), None);
+
+        // An older session is ignored.
+        let outdated = Resync {
+            session: session.wrapping_sub(1),
+            last_good_counter: 2,
+        };
+        assert_eq!(
+            leader_state.handle_resync(2, outdated, 0),
+            ResyncOutcome::Outdated
+        );
+
+        // A counter whose index has since been compacted falls back to a LogSync.
+        let compacted = Resync {
+            session,
+            last_good_counter: 1,
+        };
+        assert_eq!(
+            leader_state.handle_resync(2, compacted, 6),
+            ResyncOutcome::Fallback
+        );
+    }
+
+    #[cfg(feature = "preimage")]
+    #[test]
+    fn preimage_ref_dedupes_identical_payloads_test() {
+        let a = PreimageRef::of(b"a reasonably large command payload");
+        let b = PreimageRef::of(b"a reasonably large command payload");
+        let c = PreimageRef::of(b"a different command payload");
+        assert_eq!(a, b);
+        assert_eq!(a.len, 34);
+        assert_ne!(a.hash, c.hash);
+    }
+
+    #[cfg(feature = "preimage")]
+    #[test]
+    fn preimage_hash_matches_sha256_vector_test() {
+        // FIPS 180-4 test vector for "abc".
+        let expected =
+            hex_to_bytes("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(preimage_hash(b"abc").to_vec(), expected);
+    }
+
+    #[cfg(feature = "preimage")]
+    #[test]
+    fn preimage_table_refcounts_and_gc_test() {
+        let mut table = PreimageTable::default();
+        // Two log positions reference the same payload.
+        let r1 = table.store(b"large payload".to_vec());
+        let r2 = table.store(b"large payload".to_vec());
+        assert_eq!(r1, r2);
+        assert_eq!(table.get(&r1), Some(b"large payload".as_slice()));
+
+        // Releasing one reference keeps the payload alive for the other.
+        table.release(&r1.hash);
+        assert_eq!(table.get(&r1), Some(b"large payload".as_slice()));
+
+        // Releasing the last reference garbage-collects it.
+        table.release(&r2.hash);
+        assert_eq!(table.get(&r2), None);
+    }
+
+    #[cfg(feature = "preimage")]
+    #[test]
+    fn preimage_out_line_respects_threshold_test() {
+        let mut table = PreimageTable::default();
+        // A payload below the threshold stays inline.
+        assert_eq!(table.out_line(vec![0u8; 16]), None);
+        // A payload above the threshold is stored out-of-line and resolvable.
+        let big = vec![7u8; defaults::PREIMAGE_THRESHOLD_BYTES + 1];
+        let reference = table.out_line(big.clone()).expect("should be out-lined");
+        assert_eq!(table.resolve(&reference), Ok(big.as_slice()));
+    }
+
+    #[cfg(feature = "preimage")]
+    #[test]
+    fn preimage_resolve_missing_requests_fetch_test() {
+        let table = PreimageTable::default();
+        let reference = PreimageRef::of(b"unheld payload");
+        assert_eq!(
+            table.resolve(&reference),
+            Err(FetchPreimage {
+                hash: reference.hash
+            })
+        );
+    }
+
+    #[cfg(feature = "preimage")]
+    fn hex_to_bytes(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
 }